@@ -0,0 +1,186 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use executor::UserTask;
+use signal::SignalFlags;
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::tcp;
+use spin::Mutex;
+
+use crate::tasks::net::{register_socket_waker, SOCKETS};
+
+/// Tasks parked on a futex word, keyed by its user-space address.
+static FUTEX_QUEUE: Mutex<BTreeMap<usize, Vec<Waker>>> = Mutex::new(BTreeMap::new());
+
+/// Wakes up to `n` tasks waiting on the futex at `uaddr`.
+pub fn futex_wake(uaddr: usize, n: usize) -> usize {
+    let mut queue = FUTEX_QUEUE.lock();
+    match queue.get_mut(&uaddr) {
+        Some(wakers) => {
+            let woken = wakers.len().min(n);
+            wakers.drain(..woken).for_each(Waker::wake);
+            woken
+        }
+        None => 0,
+    }
+}
+
+/// Moves tasks parked on `uaddr` over to `new_uaddr`, for `FUTEX_REQUEUE`.
+pub fn futex_requeue(uaddr: usize, new_uaddr: usize, n: usize) -> usize {
+    let mut queue = FUTEX_QUEUE.lock();
+    let Some(wakers) = queue.get_mut(&uaddr) else {
+        return 0;
+    };
+    let moved: Vec<Waker> = wakers.drain(..wakers.len().min(n)).collect();
+    let moved_len = moved.len();
+    queue.entry(new_uaddr).or_default().extend(moved);
+    moved_len
+}
+
+/// Suspends the current task until another task calls [`futex_wake`] on
+/// the same `uaddr`.
+pub struct WaitFutex(pub usize);
+
+impl Future for WaitFutex {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        FUTEX_QUEUE
+            .lock()
+            .entry(self.0)
+            .or_default()
+            .push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// Suspends the current task until the child task `pid` has exited.
+pub struct WaitPid(pub Arc<UserTask>, pub usize);
+
+impl Future for WaitPid {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let exited = self
+            .0
+            .pcb
+            .lock()
+            .children
+            .iter()
+            .find(|child| child.get_task_id() == self.1)
+            .map_or(true, |child| child.exit_code().is_some());
+
+        if exited {
+            Poll::Ready(())
+        } else {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+/// Suspends the current task until a signal becomes pending.
+pub struct WaitSignal(pub Arc<UserTask>);
+
+impl Future for WaitSignal {
+    type Output = SignalFlags;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.0.tcb.read().signal.try_get_signal() {
+            Some(signal) => Poll::Ready(signal),
+            None => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Yields the task back to the executor for exactly one tick.
+pub struct NextTick {
+    polled: bool,
+}
+
+impl NextTick {
+    pub fn new() -> Self {
+        Self { polled: false }
+    }
+}
+
+impl Future for NextTick {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.polled {
+            Poll::Ready(())
+        } else {
+            self.polled = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+/// Suspends the current task until `handle_net` accepts a new connection
+/// on `handle`, i.e. until the TCP socket leaves the listening state.
+pub struct WaitAccept(pub SocketHandle);
+
+impl Future for WaitAccept {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut sockets = SOCKETS.lock();
+        let socket = sockets.get_mut::<tcp::Socket>(self.0);
+        if socket.is_active() {
+            Poll::Ready(())
+        } else {
+            drop(sockets);
+            register_socket_waker(self.0, cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// Suspends the current task until `handle_net` delivers data (or closes
+/// the connection) on `handle`.
+pub struct WaitSocketData(pub SocketHandle);
+
+impl Future for WaitSocketData {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut sockets = SOCKETS.lock();
+        let socket = sockets.get_mut::<tcp::Socket>(self.0);
+        if socket.can_recv() || !socket.may_recv() {
+            Poll::Ready(())
+        } else {
+            drop(sockets);
+            register_socket_waker(self.0, cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+fn noop_raw_waker() -> RawWaker {
+    fn clone(_: *const ()) -> RawWaker {
+        noop_raw_waker()
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    RawWaker::new(core::ptr::null(), &VTABLE)
+}
+
+/// Checks whether `handle` has data ready without suspending the calling
+/// task, for callers (like [`relay`](super::relay)) that multiplex several
+/// sockets in one task and can't afford to block on any single one of
+/// them. Goes through the same [`WaitSocketData`] future `recv` awaits, so
+/// both entry points agree on what "ready" means for a socket.
+pub fn poll_socket_data(handle: SocketHandle) -> Poll<()> {
+    let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+    let mut cx = Context::from_waker(&waker);
+    Pin::new(&mut WaitSocketData(handle)).poll(&mut cx)
+}
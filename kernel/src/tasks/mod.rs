@@ -1,29 +1,38 @@
 use core::{future::Future, mem::size_of};
 
 use alloc::{boxed::Box, sync::Arc, vec::Vec};
-use arch::{get_time, trap_pre_handle, user_restore, Context, ContextOps, VirtPage};
-use devices::NET_DEVICES;
+use arch::{
+    flush_tlb, frame_drop_ref, frame_ref_count, get_time, trap_pre_handle, user_restore, Context,
+    ContextOps, VirtPage,
+};
 use executor::{
     current_task, current_user_task, thread, yield_now, AsyncTask, Executor, KernelTask, MemType,
     UserTask, TASK_QUEUE,
 };
-use fs::socket::NetType;
+use hal::current_nsec;
 use log::{debug, warn};
-use lose_net_stack::{results::Packet, IPv4, LoseStack, MacAddress, TcpFlags};
 use signal::SignalFlags;
+use smoltcp::time::Instant;
 
 use crate::syscall::{
     consts::{SignalUserContext, UserRef},
-    exec_with_process, syscall, PORT_TABLE,
+    exec_with_process, syscall,
 };
+use crate::tasks::net::{init_iface, wake_socket_waiters, IFACE, SOCKETS};
 
 use self::initproc::initproc;
 
 mod async_ops;
 pub mod elf;
 mod initproc;
+pub mod net;
+pub mod relay;
+pub mod wireguard;
 
-pub use async_ops::{futex_requeue, futex_wake, NextTick, WaitFutex, WaitPid, WaitSignal};
+pub use async_ops::{
+    futex_requeue, futex_wake, poll_socket_data, NextTick, WaitAccept, WaitFutex, WaitPid,
+    WaitSignal, WaitSocketData,
+};
 
 #[no_mangle]
 // for avoiding the rust cycle check. use extern and nomangle
@@ -36,6 +45,80 @@ enum UserTaskControlFlow {
     Break,
 }
 
+// Standard riscv64 Linux syscall numbers for the socket family. `syscall`
+// (the `fs::socket`-backed dispatcher) isn't part of this tree and still
+// answers these the same way it always has, against `PORT_TABLE`, which
+// `handle_net` stopped feeding when it moved to `SOCKETS`; intercept them
+// here instead so they're answered for real.
+const SYS_SOCKET: usize = 198;
+const SYS_BIND: usize = 200;
+const SYS_LISTEN: usize = 201;
+const SYS_ACCEPT: usize = 202;
+const SYS_CONNECT: usize = 203;
+const SYS_SENDTO: usize = 206;
+const SYS_RECVFROM: usize = 207;
+const SYS_ACCEPT4: usize = 242;
+
+const SOCK_DGRAM: usize = 2;
+
+/// Answers the socket-family syscalls directly against [`net`]'s
+/// `SOCKETS`-backed implementation. Returns `None` for anything else, so
+/// `handle_syscall` falls through to the regular dispatcher.
+async fn handle_socket_syscall(call_number: usize, args: [usize; 7]) -> Option<isize> {
+    let ret = match call_number {
+        SYS_SOCKET => {
+            let net_type = if args[1] == SOCK_DGRAM {
+                fs::socket::NetType::DGRAM
+            } else {
+                fs::socket::NetType::STEAM
+            };
+            net::socket_fd_create(net_type) as isize
+        }
+        SYS_BIND => {
+            // `sockaddr_in`: family (2 bytes) then port, big-endian (2
+            // bytes), then the address; only the port matters here.
+            let sockaddr = UserRef::<u8>::from(args[1]).slice_mut_with_len(args[2].clamp(4, 16));
+            let port = u16::from_be_bytes([sockaddr[2], sockaddr[3]]);
+            match net::socket_fd_listen(args[0], port) {
+                Ok(()) => 0,
+                Err(()) => -1,
+            }
+        }
+        // `listen()`'s backlog doesn't mean anything to this socket
+        // model; `bind` above already put the socket in the listening
+        // state once a port was known.
+        SYS_LISTEN => 0,
+        SYS_ACCEPT | SYS_ACCEPT4 => match net::socket_fd_accept(args[0]).await {
+            Some(new_fd) => new_fd as isize,
+            None => -1,
+        },
+        SYS_RECVFROM => {
+            let buf = UserRef::<u8>::from(args[1]).slice_mut_with_len(args[2]);
+            match net::socket_fd_recv(args[0], buf).await {
+                Some(n) => n as isize,
+                None => -1,
+            }
+        }
+        SYS_SENDTO => {
+            let buf = UserRef::<u8>::from(args[1]).slice_mut_with_len(args[2]);
+            match net::socket_fd_send(args[0], buf) {
+                Some(n) => n as isize,
+                None => -1,
+            }
+        }
+        SYS_CONNECT => {
+            // Outbound connections still go through `net::connect_tcp`
+            // directly (see `relay.rs`). `connect()` needs the peer's
+            // address as well as its port, and nothing here decodes the
+            // address part of a `sockaddr`, so refuse rather than pretend
+            // to connect.
+            -1
+        }
+        _ => return None,
+    };
+    Some(ret)
+}
+
 async fn handle_syscall(task: Arc<UserTask>, cx_ref: &mut Context) -> UserTaskControlFlow {
     let ustart = 0;
     unsafe {
@@ -56,9 +139,12 @@ async fn handle_syscall(task: Arc<UserTask>, cx_ref: &mut Context) -> UserTaskCo
             ];
             let call_number = cx_ref.syscall_number();
             cx_ref.syscall_ok();
-            let result = syscall(call_number, args)
-                .await
-                .map_or_else(|e| -e.code(), |x| x as isize) as usize;
+            let result = match handle_socket_syscall(call_number, args).await {
+                Some(ret) => ret as usize,
+                None => syscall(call_number, args)
+                    .await
+                    .map_or_else(|e| -e.code(), |x| x as isize) as usize,
+            };
             debug!("syscall result: {:#X?}", result);
             cx_ref.set_ret(result);
             if result == (-500 as isize) as usize {
@@ -92,10 +178,34 @@ async fn handle_syscall(task: Arc<UserTask>, cx_ref: &mut Context) -> UserTaskCo
             warn!("store page: {:?}", finded);
 
             match finded {
-                Some(_) => {
-                    // let src_ppn = tracker.0;
-                    // let dst_ppn = task.frame_alloc(vpn, MemType::CodeSection);
-                    // dst_ppn.copy_value_from_another(src_ppn);
+                Some(tracker) => {
+                    let src_ppn = tracker.ppn;
+
+                    if frame_ref_count(src_ppn) <= 1 {
+                        // No sibling still shares this page; nothing to
+                        // copy against, so just remap it writable in
+                        // place instead of allocating a fresh copy.
+                        task.frame_map(vpn, src_ppn, MemType::CodeSection);
+                    } else {
+                        let dst_ppn = task.frame_alloc(vpn, MemType::CodeSection, 1);
+                        dst_ppn.copy_value_from_another(src_ppn);
+                        frame_drop_ref(src_ppn);
+                    }
+
+                    // The fault handler above just gave this task its own
+                    // writable page (remapped in place if it was the sole
+                    // remaining owner, or a fresh copy otherwise), so it
+                    // no longer shares `src_ppn` with its sibling(s) via a
+                    // `Clone` tracker. Drop that tracker for this vpn;
+                    // `src_ppn` itself is only freed once every other
+                    // sharer has done the same and its refcount hits zero.
+                    task.pcb.lock().memset.iter_mut().for_each(|mem_area| {
+                        mem_area
+                            .mtrackers
+                            .retain(|x| !(x.vpn == vpn && mem_area.mtype == MemType::Clone));
+                    });
+
+                    flush_tlb(Some(vpn));
                 }
                 None => {
                     warn!("alloc judge addr: {:#x}", addr);
@@ -218,143 +328,75 @@ pub async fn user_entry_inner() {
     debug!("exit_task: {}", current_task().get_task_id());
 }
 
-#[allow(dead_code)]
-pub async fn handle_net() {
-    let lose_stack = LoseStack::new(
-        IPv4::new(10, 0, 2, 15),
-        MacAddress::new([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]),
-    );
+/// Drives `device`'s interface: polls it every tick and wakes socket
+/// waiters on progress. Shared by both the plain and WireGuard-tunneled
+/// `handle_net` below, so the only difference between them is which
+/// `Device` gets built.
+async fn drive_net<D: smoltcp::phy::Device>(mut device: D) {
+    init_iface(&mut device);
 
-    let mut buffer = vec![0u8; 2048];
     loop {
         if TASK_QUEUE.lock().len() == 1 {
             break;
         }
-        let rlen = NET_DEVICES.lock()[0].recv(&mut buffer).unwrap_or(0);
-        if rlen != 0 {
-            let packet = lose_stack.analysis(&buffer[..rlen]);
-            debug!("packet: {:?}", packet);
-            match packet {
-                Packet::ARP(arp_packet) => {
-                    debug!("receive arp packet: {:?}", arp_packet);
-                    let reply_packet = arp_packet
-                        .reply_packet(lose_stack.ip, lose_stack.mac)
-                        .expect("can't build reply");
-                    NET_DEVICES.lock()[0]
-                        .send(&reply_packet.build_data())
-                        .expect("can't send net data");
-                }
-                Packet::UDP(udp_packet) => {
-                    debug!("udp_packet: {:?}", udp_packet);
-                }
-                Packet::TCP(tcp_packet) => {
-                    let net = NET_DEVICES.lock()[0].clone();
-                    if tcp_packet.flags == TcpFlags::S {
-                        // receive a tcp connect packet
-                        let mut reply_packet = tcp_packet.ack();
-                        reply_packet.flags = TcpFlags::S | TcpFlags::A;
-                        if let Some(socket) = PORT_TABLE.lock().get(&tcp_packet.dest_port) {
-                            // TODO: create a new socket as the child of this socket.
-                            // and this is receive a child.
-                            // TODO: specific whether it is tcp or udp
-
-                            info!(
-                                "[TCP CONNECT]{}:{}(MAC:{}) -> {}:{}(MAC:{})  len:{}",
-                                tcp_packet.source_ip,
-                                tcp_packet.source_port,
-                                tcp_packet.source_mac,
-                                tcp_packet.dest_ip,
-                                tcp_packet.dest_port,
-                                tcp_packet.dest_mac,
-                                tcp_packet.data_len
-                            );
-                            if socket.net_type == NetType::STEAM {
-                                socket.add_wait_queue(
-                                    tcp_packet.source_ip.to_u32(),
-                                    tcp_packet.source_port,
-                                );
-                                let reply_data = &reply_packet.build_data();
-                                net.send(&reply_data).expect("can't send to net");
-                            }
-                        }
-                    } else if tcp_packet.flags.contains(TcpFlags::F) {
-                        // tcp disconnected
-                        info!(
-                            "[TCP DISCONNECTED]{}:{}(MAC:{}) -> {}:{}(MAC:{})  len:{}",
-                            tcp_packet.source_ip,
-                            tcp_packet.source_port,
-                            tcp_packet.source_mac,
-                            tcp_packet.dest_ip,
-                            tcp_packet.dest_port,
-                            tcp_packet.dest_mac,
-                            tcp_packet.data_len
-                        );
-                        let reply_packet = tcp_packet.ack();
-                        net.send(&reply_packet.build_data())
-                            .expect("can't send to net");
-
-                        let mut end_packet = reply_packet.ack();
-                        end_packet.flags |= TcpFlags::F;
-                        net.send(&end_packet.build_data())
-                            .expect("can't send to net");
-                    } else {
-                        info!(
-                            "{}:{}(MAC:{}) -> {}:{}(MAC:{})  len:{}",
-                            tcp_packet.source_ip,
-                            tcp_packet.source_port,
-                            tcp_packet.source_mac,
-                            tcp_packet.dest_ip,
-                            tcp_packet.dest_port,
-                            tcp_packet.dest_mac,
-                            tcp_packet.data_len
-                        );
-
-                        if tcp_packet.flags.contains(TcpFlags::A) && tcp_packet.data_len == 0 {
-                            continue;
-                        }
-
-                        if let Some(socket) = PORT_TABLE.lock().get(&tcp_packet.dest_port) {
-                            let socket_inner = socket.inner.lock();
-                            let client = socket_inner.clients.iter().find(|x| match x.upgrade() {
-                                Some(x) => {
-                                    let client_inner = x.inner.lock();
-                                    client_inner.target_ip == tcp_packet.source_ip.to_u32()
-                                        && client_inner.target_port == tcp_packet.source_port
-                                }
-                                None => false,
-                            });
-
-                            client.map(|x| {
-                                let socket = x.upgrade().unwrap();
-                                let mut socket_inner = socket.inner.lock();
-
-                                socket_inner.datas.push_back(tcp_packet.data.to_vec());
-                                let reply = tcp_packet.reply(&[0u8; 0]);
-                                socket_inner.ack = reply.ack;
-                                socket_inner.seq = reply.seq;
-                                socket_inner.flags = reply.flags.bits();
-                            });
-                        }
-
-                        // handle tcp data
-                        // receive_tcp(&mut net, &tcp_packet)
-                    }
-                }
-                Packet::ICMP() => {}
-                Packet::IGMP() => todo!(),
-                Packet::Todo(_) => todo!(),
-                Packet::None => todo!(),
-            }
+
+        let timestamp = Instant::from_micros((current_nsec() / 1000) as i64);
+        let mut iface_guard = IFACE.lock();
+        let iface = iface_guard.as_mut().expect("net interface not initialized");
+        let mut sockets = SOCKETS.lock();
+        let progressed = iface.poll(timestamp, &mut device, &mut sockets);
+        drop(sockets);
+        drop(iface_guard);
+
+        if progressed {
+            // A connection was accepted, data arrived, or a socket closed;
+            // wake any task parked in `WaitAccept`/`WaitSocketData`.
+            wake_socket_waiters();
         }
+
         yield_now().await;
     }
 }
 
+/// Encrypts the one physical NIC behind a WireGuard tunnel. Only built
+/// when the `wg` feature is enabled. [`wireguard::configured_device`]
+/// holds placeholder keys, so with this feature on, all traffic (ARP,
+/// TCP, UDP, the relay) waits behind a handshake that cannot complete
+/// against the placeholder peer key.
+#[allow(dead_code)]
+#[cfg(feature = "wg")]
+pub async fn handle_net() {
+    drive_net(wireguard::configured_device()).await;
+}
+
+#[allow(dead_code)]
+#[cfg(not(feature = "wg"))]
+pub async fn handle_net() {
+    drive_net(net::NetDevice).await;
+}
+
 pub fn init() {
     let mut exec = Executor::new();
     exec.spawn(KernelTask::new(initproc()));
     #[cfg(feature = "net")]
     exec.spawn(KernelTask::new(handle_net()));
+    #[cfg(feature = "net")]
+    {
+        let relay_cfg = relay::RelayConfig {
+            local_port: 6000,
+            remote_addr: smoltcp::wire::IpAddress::v4(10, 0, 2, 2),
+            remote_port: 7000,
+        };
+        let relay_state = relay::new_state();
+        exec.spawn(KernelTask::new(relay::accept_guests(
+            relay_cfg,
+            relay_state.clone(),
+        )));
+        exec.spawn(KernelTask::new(relay::handle_port_forward(
+            relay_cfg,
+            relay_state,
+        )));
+    }
     // exec.spawn()
     exec.run();
 }
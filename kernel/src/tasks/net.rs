@@ -0,0 +1,342 @@
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::Waker;
+
+use devices::NET_DEVICES;
+use fs::socket::NetType;
+use hal::current_nsec;
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use smoltcp::socket::{tcp, udp};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, HardwareAddress, IpAddress, IpCidr, Ipv4Address};
+use spin::Mutex;
+
+use crate::tasks::async_ops::{WaitAccept, WaitSocketData};
+
+/// Ethernet MTU used for the single virtio-net backed interface.
+const MTU: usize = 2048;
+
+/// The static MAC/IP this kernel currently presents on the net device.
+/// Matches the addressing the old `lose_net_stack`-based `handle_net` used.
+/// `pub(crate)` so [`wireguard`](super::wireguard) can address its
+/// encapsulated datagrams from the same identity the plaintext interface
+/// above it uses.
+pub(crate) const MAC_ADDR: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+pub(crate) const LOCAL_IPV4: Ipv4Address = Ipv4Address::new(10, 0, 2, 15);
+
+/// `smoltcp::phy::Device` adapter over `NET_DEVICES[0]`.
+///
+/// `receive`/`transmit` hand out tokens that copy straight to/from the
+/// underlying device's `recv`/`send`, so smoltcp drives retransmission,
+/// windowing and teardown instead of the ad-hoc packet handling this
+/// replaces.
+pub struct NetDevice;
+
+pub struct NetRxToken(Vec<u8>);
+pub struct NetTxToken;
+
+impl From<Vec<u8>> for NetRxToken {
+    fn from(buffer: Vec<u8>) -> Self {
+        Self(buffer)
+    }
+}
+
+impl RxToken for NetRxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.0)
+    }
+}
+
+impl TxToken for NetTxToken {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut buffer = vec![0u8; len];
+        let result = f(&mut buffer);
+        NET_DEVICES.lock()[0]
+            .send(&buffer)
+            .expect("can't send net data");
+        result
+    }
+}
+
+impl NetDevice {
+    /// Sends a raw frame directly through the underlying device, bypassing
+    /// smoltcp's own tx token flow. Used by wrapper devices (see
+    /// [`wireguard`](super::wireguard)) that need to emit datagrams, such
+    /// as a handshake message, that don't correspond to a plaintext frame
+    /// `Interface::poll` asked to transmit.
+    pub fn send_raw(&self, data: &[u8]) -> Result<(), &'static str> {
+        NET_DEVICES.lock()[0]
+            .send(data)
+            .map_err(|_| "can't send net data")
+    }
+}
+
+impl Device for NetDevice {
+    type RxToken<'a> = NetRxToken;
+    type TxToken<'a> = NetTxToken;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let mut buffer = vec![0u8; MTU];
+        let rlen = NET_DEVICES.lock()[0].recv(&mut buffer).unwrap_or(0);
+        if rlen == 0 {
+            return None;
+        }
+        buffer.truncate(rlen);
+        Some((NetRxToken(buffer), NetTxToken))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(NetTxToken)
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = MTU;
+        caps.medium = Medium::Ethernet;
+        caps
+    }
+}
+
+/// Builds the single `Interface` this kernel drives, configured with the
+/// same IP/MAC the previous `lose_net_stack`-based loop used, plus a
+/// neighbor cache for ARP resolution. Generic over the device so either
+/// the plain [`NetDevice`] or a wrapper like
+/// [`WgDevice`](super::wireguard::WgDevice) can build it.
+pub fn build_iface<D: Device>(device: &mut D) -> Interface {
+    let mac = EthernetAddress(MAC_ADDR);
+    let config = Config::new(HardwareAddress::Ethernet(mac));
+    let now = Instant::from_micros((current_nsec() / 1000) as i64);
+
+    let mut iface = Interface::new(config, device, now);
+    iface.update_ip_addrs(|ip_addrs| {
+        ip_addrs
+            .push(IpCidr::new(IpAddress::Ipv4(LOCAL_IPV4), 24))
+            .expect("iface should only be initialized once");
+    });
+    iface
+}
+
+/// The single `Interface` `handle_net` drives, shared so other kernel
+/// tasks (e.g. [`relay`](super::relay)) can connect/listen sockets using
+/// its context without needing their own device and interface.
+pub static IFACE: Mutex<Option<Interface>> = Mutex::new(None);
+
+/// Builds and installs the global interface if it hasn't been already.
+/// `handle_net` calls this once before entering its poll loop.
+pub fn init_iface<D: Device>(device: &mut D) {
+    let mut iface = IFACE.lock();
+    if iface.is_none() {
+        *iface = Some(build_iface(device));
+    }
+}
+
+/// Global socket pool the socket syscalls (see [`socket_fd_create`])
+/// allocate TCP/UDP handles from, replacing the old `PORT_TABLE`-only
+/// bookkeeping.
+pub static SOCKETS: Mutex<SocketSet> = Mutex::new(SocketSet::new(Vec::new()));
+
+/// Connects a TCP socket using the shared interface's context. Lets
+/// consumers outside `handle_net` (like the port-forwarding relay) open
+/// outbound connections without holding their own `Interface`.
+pub fn connect_tcp(
+    handle: SocketHandle,
+    remote: (IpAddress, u16),
+    local_port: u16,
+) -> Result<(), tcp::ConnectError> {
+    let mut iface = IFACE.lock();
+    let iface = iface.as_mut().expect("net interface not initialized");
+    let mut sockets = SOCKETS.lock();
+    let socket = sockets.get_mut::<tcp::Socket>(handle);
+    socket.connect(iface.context(), remote, local_port)
+}
+
+/// Allocates a fresh TCP socket with the buffer sizes the socket layer
+/// has always used and registers it in the shared [`SOCKETS`] set.
+pub fn new_tcp_socket() -> SocketHandle {
+    let rx_buffer = tcp::SocketBuffer::new(vec![0u8; 4096]);
+    let tx_buffer = tcp::SocketBuffer::new(vec![0u8; 4096]);
+    SOCKETS.lock().add(tcp::Socket::new(rx_buffer, tx_buffer))
+}
+
+/// Allocates a fresh UDP socket with a handful of datagram slots, mirroring
+/// `new_tcp_socket`.
+pub fn new_udp_socket() -> SocketHandle {
+    let rx_buffer = udp::PacketBuffer::new(
+        vec![udp::PacketMetadata::EMPTY; 16],
+        vec![0u8; 4096],
+    );
+    let tx_buffer = udp::PacketBuffer::new(
+        vec![udp::PacketMetadata::EMPTY; 16],
+        vec![0u8; 4096],
+    );
+    SOCKETS.lock().add(udp::Socket::new(rx_buffer, tx_buffer))
+}
+
+/// Allocates a socket of the requested type and registers it in
+/// [`SOCKETS`]. This is the entry point the `socket()` syscall (via
+/// [`socket_fd_create`]) calls, replacing the old
+/// `PORT_TABLE`-based allocation; the port-forwarding relay
+/// (see [`relay`](super::relay)) goes through it too, rather than
+/// allocating sockets directly, so it exercises the same path real
+/// user sockets do.
+pub fn create_socket(net_type: NetType) -> SocketHandle {
+    match net_type {
+        NetType::STEAM => new_tcp_socket(),
+        NetType::DGRAM => new_udp_socket(),
+    }
+}
+
+/// Tasks parked in [`WaitAccept`](super::async_ops::WaitAccept) or
+/// [`WaitSocketData`](super::async_ops::WaitSocketData), keyed by the
+/// socket handle they're waiting on.
+static WAKERS: Mutex<BTreeMap<SocketHandle, Vec<Waker>>> = Mutex::new(BTreeMap::new());
+
+/// Registers `waker` to be woken the next time `handle_net` observes
+/// progress on `handle` (a new connection, new data, or a close).
+pub fn register_socket_waker(handle: SocketHandle, waker: Waker) {
+    let mut wakers = WAKERS.lock();
+    let list = wakers.entry(handle).or_default();
+    if !list.iter().any(|w| w.will_wake(&waker)) {
+        list.push(waker);
+    }
+}
+
+/// Wakes every task waiting on any socket. Called by `handle_net` after
+/// each `iface.poll()` that reports a readiness change, so blocked
+/// `accept`/`recv` syscalls re-check their socket and resume.
+pub fn wake_socket_waiters() {
+    let mut wakers = WAKERS.lock();
+    for (_, list) in wakers.iter_mut() {
+        list.drain(..).for_each(Waker::wake);
+    }
+}
+
+/// Accepts one connection on `listener`, suspending the calling task (via
+/// [`WaitAccept`]) instead of busy-polling until `handle_net` reports the
+/// socket active. Spins up a fresh socket listening on the same `port` so
+/// further connections can still be accepted, and returns its handle; the
+/// caller keeps using `listener` itself as the now-established
+/// connection. The `accept()` syscall is built on it by
+/// [`socket_fd_accept`].
+pub async fn accept(listener: SocketHandle, port: u16) -> SocketHandle {
+    WaitAccept(listener).await;
+
+    let next = new_tcp_socket();
+    let mut sockets = SOCKETS.lock();
+    let _ = sockets.get_mut::<tcp::Socket>(next).listen(port);
+    next
+}
+
+/// Reads from `handle`, suspending the calling task (via
+/// [`WaitSocketData`]) until data is available or the peer has closed the
+/// connection. The `recv()` syscall is built on it by
+/// [`socket_fd_recv`].
+pub async fn recv(handle: SocketHandle, buf: &mut [u8]) -> usize {
+    WaitSocketData(handle).await;
+
+    let mut sockets = SOCKETS.lock();
+    let socket = sockets.get_mut::<tcp::Socket>(handle);
+    socket.recv_slice(buf).unwrap_or(0)
+}
+
+/// Writes to `handle`. The `send()` syscall is built on it by
+/// [`socket_fd_send`]; unlike `recv` it doesn't need to suspend the caller,
+/// since a full send buffer just means a short write, same as a pipe.
+pub fn send(handle: SocketHandle, buf: &[u8]) -> usize {
+    let mut sockets = SOCKETS.lock();
+    let socket = sockets.get_mut::<tcp::Socket>(handle);
+    socket.send_slice(buf).unwrap_or(0)
+}
+
+/// A single entry of the fd-indexed table below: the socket it currently
+/// names, and the port it's bound to (0 until `listen`), needed to
+/// re-`listen()` the replacement socket `accept` hands back.
+#[derive(Clone, Copy)]
+struct SocketFd {
+    handle: SocketHandle,
+    port: u16,
+}
+
+/// Maps file descriptors to [`SOCKETS`] handles.
+///
+/// This exists because the real per-task fd table lives in `fs::socket`,
+/// which isn't part of this tree; the syscall dispatch in
+/// [`handle_syscall`](super::handle_syscall) still needs *some* fd ->
+/// handle mapping to implement `socket()`/`bind()`/`listen()`/`accept()`/
+/// `recv()`/`send()` against `SOCKETS`, so this stands in for it. It's
+/// process-agnostic (one flat fd space, not one per task) — good enough
+/// until real fd-table plumbing replaces it.
+static SOCKET_FDS: Mutex<BTreeMap<usize, SocketFd>> = Mutex::new(BTreeMap::new());
+
+/// Fds 0-2 are conventionally stdio; hand out socket fds starting past
+/// them.
+static NEXT_SOCKET_FD: AtomicUsize = AtomicUsize::new(3);
+
+/// `socket()`: allocates a socket of `net_type` and returns a fresh fd
+/// for it.
+pub fn socket_fd_create(net_type: NetType) -> usize {
+    let handle = create_socket(net_type);
+    let fd = NEXT_SOCKET_FD.fetch_add(1, Ordering::Relaxed);
+    SOCKET_FDS.lock().insert(fd, SocketFd { handle, port: 0 });
+    fd
+}
+
+/// Looks up the socket `fd` currently names.
+pub fn socket_fd_handle(fd: usize) -> Option<SocketHandle> {
+    SOCKET_FDS.lock().get(&fd).map(|entry| entry.handle)
+}
+
+/// `bind()`/`listen()`: records `port` against `fd` and starts listening.
+pub fn socket_fd_listen(fd: usize, port: u16) -> Result<(), ()> {
+    let mut fds = SOCKET_FDS.lock();
+    let entry = fds.get_mut(&fd).ok_or(())?;
+    entry.port = port;
+    let handle = entry.handle;
+    drop(fds);
+    SOCKETS
+        .lock()
+        .get_mut::<tcp::Socket>(handle)
+        .listen(port)
+        .map_err(|_| ())
+}
+
+/// `accept()`: suspends until a connection arrives on `fd`, same as
+/// [`accept`]. Matching POSIX semantics (unlike [`accept`] itself), `fd`
+/// keeps listening for the next connection and the established
+/// connection is handed back under a fresh fd.
+pub async fn socket_fd_accept(fd: usize) -> Option<usize> {
+    let (listener, port) = {
+        let fds = SOCKET_FDS.lock();
+        let entry = fds.get(&fd)?;
+        (entry.handle, entry.port)
+    };
+
+    let replacement = accept(listener, port).await;
+
+    let mut fds = SOCKET_FDS.lock();
+    fds.insert(fd, SocketFd { handle: replacement, port });
+    let new_fd = NEXT_SOCKET_FD.fetch_add(1, Ordering::Relaxed);
+    fds.insert(new_fd, SocketFd { handle: listener, port });
+    Some(new_fd)
+}
+
+/// `recv()` on a fd, same as [`recv`].
+pub async fn socket_fd_recv(fd: usize, buf: &mut [u8]) -> Option<usize> {
+    let handle = socket_fd_handle(fd)?;
+    Some(recv(handle, buf).await)
+}
+
+/// `send()` on a fd, same as [`send`].
+pub fn socket_fd_send(fd: usize, buf: &[u8]) -> Option<usize> {
+    let handle = socket_fd_handle(fd)?;
+    Some(send(handle, buf))
+}
@@ -0,0 +1,570 @@
+//! A minimal WireGuard-style (Noise_IK) encrypted tunnel device.
+//!
+//! This layers over the plain [`NetDevice`](super::net::NetDevice): the
+//! handshake and transport messages defined here travel inside UDP, and
+//! once a session is established, [`WgDevice::receive`]/[`transmit`]
+//! transparently decrypt/encrypt so everything above (smoltcp's
+//! `Interface`, and therefore `handle_net`) only ever sees plaintext
+//! Ethernet frames.
+
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use arch::get_time;
+use chacha20poly1305::aead::{Aead, AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hal::current_nsec;
+use hkdf::Hkdf;
+use sha2::Sha256 as HkdfHash;
+use blake2::{Blake2s256, Digest};
+use smoltcp::phy::{ChecksumCapabilities, Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use smoltcp::time::Instant;
+use smoltcp::wire::{
+    EthernetAddress, EthernetFrame, EthernetProtocol, EthernetRepr, IpAddress, IpProtocol,
+    Ipv4Address, Ipv4Packet, Ipv4Repr, UdpPacket, UdpRepr,
+};
+use spin::Mutex;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use super::net::{NetDevice, NetRxToken, LOCAL_IPV4, MAC_ADDR};
+
+/// Rekey once a session has sealed this many transport messages, a much
+/// lower bound than WireGuard's own `REKEY_AFTER_MESSAGES` (2^60).
+const REKEY_AFTER_MESSAGES: u64 = 1 << 20;
+
+/// Retry an unanswered handshake-initiation after this many milliseconds.
+const HANDSHAKE_RETRY_MS: usize = 5_000;
+
+const MSG_HANDSHAKE_INITIATION: u8 = 1;
+const MSG_HANDSHAKE_RESPONSE: u8 = 2;
+const MSG_TRANSPORT_DATA: u8 = 4;
+
+const NOISE_CONSTRUCTION: &[u8] = b"Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s";
+const IDENTIFIER: &[u8] = b"ByteOS WireGuard v1";
+
+/// UDP port handshake/transport messages are encapsulated on, matching
+/// WireGuard's own default.
+const WG_PORT: u16 = 51820;
+
+/// QEMU/slirp's fixed gateway MAC, used as the Ethernet destination for
+/// encapsulated datagrams since this device sits below smoltcp's own ARP
+/// resolution and has no neighbor cache of its own.
+const GATEWAY_MAC: EthernetAddress = EthernetAddress([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]);
+
+/// Wraps a handshake/transport `payload` in a real Ethernet/IPv4/UDP frame
+/// addressed to `(dst_ip, dst_port)`, so it reads as an ordinary UDP
+/// datagram on the wire instead of bare WireGuard message bytes.
+fn encapsulate_udp(payload: &[u8], dst_ip: Ipv4Address, dst_port: u16) -> Vec<u8> {
+    let local_ip = LOCAL_IPV4;
+
+    let udp_repr = UdpRepr {
+        src_port: WG_PORT,
+        dst_port,
+    };
+    let ip_repr = Ipv4Repr {
+        src_addr: local_ip,
+        dst_addr: dst_ip,
+        next_header: IpProtocol::Udp,
+        payload_len: udp_repr.header_len() + payload.len(),
+        hop_limit: 64,
+    };
+    let eth_repr = EthernetRepr {
+        src_addr: EthernetAddress(MAC_ADDR),
+        dst_addr: GATEWAY_MAC,
+        ethertype: EthernetProtocol::Ipv4,
+    };
+
+    let mut buffer = vec![0u8; eth_repr.buffer_len() + ip_repr.buffer_len() + udp_repr.header_len() + payload.len()];
+
+    let mut eth_frame = EthernetFrame::new_unchecked(&mut buffer);
+    eth_repr.emit(&mut eth_frame);
+
+    let mut ip_packet = Ipv4Packet::new_unchecked(eth_frame.payload_mut());
+    ip_repr.emit(&mut ip_packet, &ChecksumCapabilities::default());
+
+    let mut udp_packet = UdpPacket::new_unchecked(ip_packet.payload_mut());
+    udp_repr.emit(
+        &mut udp_packet,
+        &IpAddress::Ipv4(local_ip),
+        &IpAddress::Ipv4(dst_ip),
+        payload.len(),
+        |buf| buf.copy_from_slice(payload),
+        &ChecksumCapabilities::default(),
+    );
+
+    buffer
+}
+
+/// The inverse of [`encapsulate_udp`]: strips the Ethernet/IPv4/UDP
+/// headers off an inbound frame and returns the WireGuard payload, or
+/// `None` if it isn't a UDP datagram addressed to [`WG_PORT`].
+fn decapsulate_udp(frame: &[u8]) -> Option<Vec<u8>> {
+    let eth_frame = EthernetFrame::new_checked(frame).ok()?;
+    if eth_frame.ethertype() != EthernetProtocol::Ipv4 {
+        return None;
+    }
+    let ip_packet = Ipv4Packet::new_checked(eth_frame.payload()).ok()?;
+    if ip_packet.next_header() != IpProtocol::Udp {
+        return None;
+    }
+    let udp_packet = UdpPacket::new_checked(ip_packet.payload()).ok()?;
+    if udp_packet.dst_port() != WG_PORT {
+        return None;
+    }
+    Some(udp_packet.payload().to_vec())
+}
+
+fn blake2s_hash(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Blake2s256::new();
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+/// HKDF (extract, then expand) over SHA-256 with the chaining key as salt,
+/// standing in for WireGuard's HMAC-BLAKE2s `Kdf1`/`Kdf2`.
+fn kdf(chaining_key: &[u8; 32], input: &[u8], outputs: &mut [&mut [u8; 32]]) {
+    let hk = Hkdf::<HkdfHash>::new(Some(chaining_key), input);
+    let mut okm = vec![0u8; 32 * outputs.len()];
+    hk.expand(&[], &mut okm).expect("hkdf output too long");
+    for (i, out) in outputs.iter_mut().enumerate() {
+        out.copy_from_slice(&okm[i * 32..(i + 1) * 32]);
+    }
+}
+
+/// Running Noise_IK transcript hash and chaining key, plus the local
+/// ephemeral keypair used to set up one handshake attempt.
+struct HandshakeState {
+    chaining_key: [u8; 32],
+    hash: [u8; 32],
+    local_ephemeral: Option<EphemeralSecret>,
+    local_ephemeral_public: PublicKey,
+}
+
+impl HandshakeState {
+    fn new(local_static_public: &PublicKey, remote_static_public: &PublicKey) -> Self {
+        let chaining_key = blake2s_hash(NOISE_CONSTRUCTION);
+        let mut hash_input = Vec::with_capacity(64);
+        hash_input.extend_from_slice(&chaining_key);
+        hash_input.extend_from_slice(IDENTIFIER);
+        let hash = blake2s_hash(&hash_input);
+
+        let mut hash_input = Vec::with_capacity(64);
+        hash_input.extend_from_slice(&hash);
+        hash_input.extend_from_slice(remote_static_public.as_bytes());
+        let hash = blake2s_hash(&hash_input);
+
+        let _ = local_static_public;
+        Self {
+            chaining_key,
+            hash,
+            local_ephemeral: None,
+            local_ephemeral_public: PublicKey::from([0u8; 32]),
+        }
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut input = Vec::with_capacity(self.hash.len() + data.len());
+        input.extend_from_slice(&self.hash);
+        input.extend_from_slice(data);
+        self.hash = blake2s_hash(&input);
+    }
+
+    fn mix_key(&mut self, input: &[u8]) {
+        let mut new_ck = [0u8; 32];
+        kdf(&self.chaining_key, input, &mut [&mut new_ck]);
+        self.chaining_key = new_ck;
+    }
+}
+
+/// The symmetric keys used to seal/open transport data packets for one
+/// completed handshake, plus the monotonic counter that supplies the send
+/// key's nonces (the receive side takes its nonce from each message).
+struct TransportKeys {
+    send: Key,
+    send_counter: AtomicU64,
+    recv: Key,
+}
+
+/// One WireGuard peer: its static public key, the in-flight handshake (if
+/// any) and the established transport keys (if any).
+pub struct Peer {
+    static_public: PublicKey,
+    endpoint: Option<([u8; 4], u16)>,
+    handshake: Mutex<Option<HandshakeState>>,
+    transport: Mutex<Option<TransportKeys>>,
+    last_handshake_attempt_ms: AtomicU64,
+}
+
+impl Peer {
+    pub fn new(static_public: PublicKey, endpoint: ([u8; 4], u16)) -> Self {
+        Self {
+            static_public,
+            endpoint: Some(endpoint),
+            handshake: Mutex::new(None),
+            transport: Mutex::new(None),
+            last_handshake_attempt_ms: AtomicU64::new(0),
+        }
+    }
+
+    /// Builds and returns the handshake-initiation message, starting (or
+    /// retrying, per `HANDSHAKE_RETRY_MS`) a new handshake attempt.
+    fn initiate_handshake(&self, local_static: &StaticSecret) -> Option<Vec<u8>> {
+        let now = (current_nsec() / 1_000_000) as u64;
+        let last = self.last_handshake_attempt_ms.load(Ordering::Relaxed);
+        // `last == 0` means no attempt yet, so the first one isn't delayed.
+        if last != 0 && now.saturating_sub(last) < HANDSHAKE_RETRY_MS as u64 {
+            return None;
+        }
+        self.last_handshake_attempt_ms.store(now, Ordering::Relaxed);
+
+        let local_static_public = PublicKey::from(local_static);
+        let mut hs = HandshakeState::new(&local_static_public, &self.static_public);
+
+        let ephemeral = EphemeralSecret::random();
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        hs.mix_hash(ephemeral_public.as_bytes());
+        hs.mix_key(ephemeral_public.as_bytes());
+
+        // DH(e_priv, peer_static_pub) seeds the key used to encrypt our
+        // static public key and a timestamp, per Noise_IK.
+        let es = ephemeral.diffie_hellman(&self.static_public);
+        hs.mix_key(es.as_bytes());
+
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&hs.chaining_key));
+        let encrypted_static = cipher
+            .encrypt(Nonce::from_slice(&[0u8; 12]), local_static_public.as_bytes().as_slice())
+            .expect("encrypt static key");
+        hs.mix_hash(&encrypted_static);
+
+        let ss = local_static.diffie_hellman(&self.static_public);
+        hs.mix_key(ss.as_bytes());
+
+        let timestamp = (get_time() as u64).to_le_bytes();
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&hs.chaining_key));
+        let encrypted_timestamp = cipher
+            .encrypt(Nonce::from_slice(&[0u8; 12]), timestamp.as_slice())
+            .expect("encrypt timestamp");
+        hs.mix_hash(&encrypted_timestamp);
+
+        let mut msg = Vec::with_capacity(1 + 32 + encrypted_static.len() + encrypted_timestamp.len());
+        msg.push(MSG_HANDSHAKE_INITIATION);
+        msg.extend_from_slice(ephemeral_public.as_bytes());
+        msg.extend_from_slice(&encrypted_static);
+        msg.extend_from_slice(&encrypted_timestamp);
+
+        hs.local_ephemeral = Some(ephemeral);
+        hs.local_ephemeral_public = ephemeral_public;
+        *self.handshake.lock() = Some(hs);
+
+        Some(msg)
+    }
+
+    /// Consumes our half of a handshake response and derives the
+    /// transport send/receive keys for the new session.
+    fn complete_handshake(&self, remote_ephemeral_public: &PublicKey) {
+        let mut guard = self.handshake.lock();
+        let Some(hs) = guard.as_mut() else { return };
+        let Some(local_ephemeral) = hs.local_ephemeral.take() else {
+            return;
+        };
+
+        // Mix in the responder's ephemeral key the same way
+        // `respond_to_handshake` does, so both chaining keys match.
+        hs.mix_hash(remote_ephemeral_public.as_bytes());
+        hs.mix_key(remote_ephemeral_public.as_bytes());
+
+        let ee = local_ephemeral.diffie_hellman(remote_ephemeral_public);
+        hs.mix_key(ee.as_bytes());
+
+        let mut send = [0u8; 32];
+        let mut recv = [0u8; 32];
+        kdf(&hs.chaining_key, &[], &mut [&mut send, &mut recv]);
+
+        *self.transport.lock() = Some(TransportKeys {
+            send: *Key::from_slice(&send),
+            send_counter: AtomicU64::new(0),
+            recv: *Key::from_slice(&recv),
+        });
+        *guard = None;
+    }
+
+    /// Seals `plaintext` as a transport data message, or `None` if no
+    /// session has been established yet (the caller should trigger a
+    /// handshake instead).
+    fn seal(&self, plaintext: &[u8]) -> Option<Vec<u8>> {
+        let mut transport = self.transport.lock();
+        let keys = transport.as_ref()?;
+
+        let counter = keys.send_counter.fetch_add(1, Ordering::Relaxed);
+        if counter >= REKEY_AFTER_MESSAGES {
+            // Force the next `transmit` to see `transport.is_none()` and
+            // kick off a fresh handshake, instead of sealing silently
+            // failing forever once the nonce space is exhausted.
+            *transport = None;
+            return None;
+        }
+        let send_key = keys.send.clone();
+        drop(transport);
+
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&counter.to_le_bytes());
+
+        let cipher = ChaCha20Poly1305::new(&send_key);
+        let mut buffer = plaintext.to_vec();
+        cipher
+            .encrypt_in_place(Nonce::from_slice(&nonce), b"", &mut buffer)
+            .expect("seal transport data");
+
+        let mut msg = Vec::with_capacity(1 + 8 + buffer.len());
+        msg.push(MSG_TRANSPORT_DATA);
+        msg.extend_from_slice(&counter.to_le_bytes());
+        msg.extend_from_slice(&buffer);
+        Some(msg)
+    }
+
+    /// Processes an inbound handshake-initiation message addressed to us,
+    /// completing the Noise_IK handshake from the responder's side and
+    /// deriving transport keys. Returns the handshake-response message to
+    /// send back, or `None` if the message didn't decrypt or didn't come
+    /// from this peer's static key.
+    fn respond_to_handshake(&self, local_static: &StaticSecret, msg: &[u8]) -> Option<Vec<u8>> {
+        // type(1) + initiator ephemeral(32) + encrypted static(32+16) + encrypted timestamp(8+16)
+        if msg.len() < 1 + 32 + 48 + 24 {
+            return None;
+        }
+
+        let local_static_public = PublicKey::from(local_static);
+        let mut hs = HandshakeState::new(&local_static_public, &local_static_public);
+
+        let mut remote_ephemeral_bytes = [0u8; 32];
+        remote_ephemeral_bytes.copy_from_slice(&msg[1..33]);
+        let remote_ephemeral_public = PublicKey::from(remote_ephemeral_bytes);
+        hs.mix_hash(remote_ephemeral_public.as_bytes());
+        hs.mix_key(remote_ephemeral_public.as_bytes());
+
+        let es = local_static.diffie_hellman(&remote_ephemeral_public);
+        hs.mix_key(es.as_bytes());
+
+        let encrypted_static = &msg[33..33 + 48];
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&hs.chaining_key));
+        let initiator_static_bytes = cipher
+            .decrypt(Nonce::from_slice(&[0u8; 12]), encrypted_static)
+            .ok()?;
+        hs.mix_hash(encrypted_static);
+        let mut initiator_static_arr = [0u8; 32];
+        initiator_static_arr.copy_from_slice(&initiator_static_bytes);
+        let initiator_static_public = PublicKey::from(initiator_static_arr);
+
+        // The IK pattern only guarantees privacy/authenticity once both
+        // static keys are known; make sure the initiator is actually the
+        // peer we're configured to tunnel with.
+        if initiator_static_public.as_bytes() != self.static_public.as_bytes() {
+            return None;
+        }
+
+        let ss = local_static.diffie_hellman(&initiator_static_public);
+        hs.mix_key(ss.as_bytes());
+
+        let encrypted_timestamp = &msg[33 + 48..33 + 48 + 24];
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&hs.chaining_key));
+        cipher
+            .decrypt(Nonce::from_slice(&[0u8; 12]), encrypted_timestamp)
+            .ok()?;
+        hs.mix_hash(encrypted_timestamp);
+
+        let response_ephemeral = EphemeralSecret::random();
+        let response_ephemeral_public = PublicKey::from(&response_ephemeral);
+        hs.mix_hash(response_ephemeral_public.as_bytes());
+        hs.mix_key(response_ephemeral_public.as_bytes());
+
+        let ee = response_ephemeral.diffie_hellman(&remote_ephemeral_public);
+        hs.mix_key(ee.as_bytes());
+
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&hs.chaining_key));
+        let confirm = cipher
+            .encrypt(Nonce::from_slice(&[0u8; 12]), b"".as_slice())
+            .expect("encrypt handshake confirmation");
+        hs.mix_hash(&confirm);
+
+        // Same two keys the initiator derived, just swapped: what they
+        // send with, we receive with, and vice versa.
+        let mut initiator_send = [0u8; 32];
+        let mut initiator_recv = [0u8; 32];
+        kdf(
+            &hs.chaining_key,
+            &[],
+            &mut [&mut initiator_send, &mut initiator_recv],
+        );
+        *self.transport.lock() = Some(TransportKeys {
+            send: *Key::from_slice(&initiator_recv),
+            send_counter: AtomicU64::new(0),
+            recv: *Key::from_slice(&initiator_send),
+        });
+
+        let mut response = Vec::with_capacity(1 + 32 + confirm.len());
+        response.push(MSG_HANDSHAKE_RESPONSE);
+        response.extend_from_slice(response_ephemeral_public.as_bytes());
+        response.extend_from_slice(&confirm);
+        Some(response)
+    }
+
+    /// Opens an incoming transport data message, returning the decrypted
+    /// payload.
+    fn open(&self, counter: u64, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let transport = self.transport.lock();
+        let keys = transport.as_ref()?;
+
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&counter.to_le_bytes());
+
+        let cipher = ChaCha20Poly1305::new(&keys.recv);
+        let mut buffer = ciphertext.to_vec();
+        cipher
+            .decrypt_in_place(Nonce::from_slice(&nonce), b"", &mut buffer)
+            .ok()?;
+        Some(buffer)
+    }
+}
+
+/// Encrypted device wrapper: plaintext frames go in/out the top
+/// (`Device::receive`/`transmit`), encrypted UDP datagrams go in/out the
+/// bottom (the wrapped [`NetDevice`]).
+pub struct WgDevice {
+    inner: NetDevice,
+    local_static: StaticSecret,
+    peer: Peer,
+    plaintext_rx: VecDeque<Vec<u8>>,
+}
+
+impl WgDevice {
+    pub fn new(local_static: StaticSecret, peer: Peer) -> Self {
+        Self {
+            inner: NetDevice,
+            local_static,
+            peer,
+            plaintext_rx: VecDeque::new(),
+        }
+    }
+
+    /// Encapsulates `payload` in Ethernet/IPv4/UDP addressed to the peer's
+    /// endpoint and sends it through the underlying device, bypassing
+    /// smoltcp's own tx token flow (same as [`NetDevice::send_raw`]).
+    fn send_to_peer(&self, payload: &[u8]) {
+        let (addr, port) = self.peer.endpoint.unwrap_or(([10, 0, 2, 2], WG_PORT));
+        let dst_ip = Ipv4Address::new(addr[0], addr[1], addr[2], addr[3]);
+        let datagram = encapsulate_udp(payload, dst_ip, port);
+        let _ = self.inner.send_raw(&datagram);
+    }
+
+    /// Pulls and decrypts whatever is waiting on the underlying device,
+    /// handling handshake messages in place and queuing decrypted
+    /// transport payloads for [`Device::receive`].
+    fn pump(&mut self) {
+        while let Some((rx, _tx)) = Device::receive(&mut self.inner, Instant::from_millis(get_time() as i64)) {
+            let raw = rx.consume(|buf| buf.to_vec());
+            let Some(frame) = decapsulate_udp(&raw) else {
+                continue;
+            };
+            let Some(&msg_type) = frame.first() else {
+                continue;
+            };
+            match msg_type {
+                MSG_HANDSHAKE_INITIATION if frame.len() >= 1 + 32 + 48 + 24 => {
+                    if let Some(response) =
+                        self.peer.respond_to_handshake(&self.local_static, &frame)
+                    {
+                        self.send_to_peer(&response);
+                    }
+                }
+                MSG_HANDSHAKE_RESPONSE if frame.len() >= 33 => {
+                    let mut ephemeral_bytes = [0u8; 32];
+                    ephemeral_bytes.copy_from_slice(&frame[1..33]);
+                    self.peer
+                        .complete_handshake(&PublicKey::from(ephemeral_bytes));
+                }
+                MSG_TRANSPORT_DATA if frame.len() > 9 => {
+                    let mut counter_bytes = [0u8; 8];
+                    counter_bytes.copy_from_slice(&frame[1..9]);
+                    let counter = u64::from_le_bytes(counter_bytes);
+                    if let Some(plaintext) = self.peer.open(counter, &frame[9..]) {
+                        self.plaintext_rx.push_back(plaintext);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Device for WgDevice {
+    type RxToken<'a> = NetRxToken;
+    type TxToken<'a> = WgTxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        self.pump();
+        let plaintext = self.plaintext_rx.pop_front()?;
+        Some((NetRxToken::from(plaintext), WgTxToken { device: self }))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        if self.peer.transport.lock().is_none() {
+            if let Some(init) = self.peer.initiate_handshake(&self.local_static) {
+                self.send_to_peer(&init);
+            }
+        }
+        Some(WgTxToken { device: self })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = self.inner.capabilities();
+        // Each plaintext frame is sent inside Ethernet (14) + IPv4 (20) +
+        // UDP (8) headers, a transport header (type 1 + counter 8) and a
+        // 16-byte AEAD tag.
+        caps.max_transmission_unit = caps
+            .max_transmission_unit
+            .saturating_sub(14 + 20 + 8 + 1 + 8 + 16);
+        caps.medium = Medium::Ethernet;
+        caps
+    }
+}
+
+/// Builds the `WgDevice` `handle_net` drives, for the one peer this kernel
+/// currently tunnels to.
+///
+/// The local/peer keys here are placeholders until real key provisioning
+/// (e.g. a config blob passed in at boot) lands; swap them out without
+/// touching anything else, since nothing downstream cares how a `WgDevice`
+/// got its keys.
+pub fn configured_device() -> WgDevice {
+    let local_static = StaticSecret::from([0x42u8; 32]);
+    let peer_static = PublicKey::from([0x24u8; 32]);
+    let peer_endpoint = ([10, 0, 2, 2], WG_PORT);
+    WgDevice::new(local_static, Peer::new(peer_static, peer_endpoint))
+}
+
+pub struct WgTxToken<'a> {
+    device: &'a mut WgDevice,
+}
+
+impl<'a> TxToken for WgTxToken<'a> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut buffer = vec![0u8; len];
+        let result = f(&mut buffer);
+        match self.device.peer.seal(&buffer) {
+            Some(sealed) => {
+                self.device.send_to_peer(&sealed);
+            }
+            None => {
+                // No session (none yet, or `seal` just dropped it at the
+                // rekey limit); `transmit` starts or retries a handshake
+                // whenever there's no session, so just drop this frame.
+            }
+        }
+        result
+    }
+}